@@ -8,18 +8,39 @@
 use std::collections::HashMap;
 use std::env;
 use std::fs;
-use std::path::Path;
+use std::path::{Path, PathBuf};
+use std::process::Stdio;
 use std::sync::Arc;
 
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
 use image_rs::image::ImageClient;
+use serde::Deserialize;
+use tokio::io::AsyncWriteExt;
+use tokio::process::Command;
 use tokio::sync::Mutex;
 
-use crate::rpc::CONTAINER_BASE;
+use crate::cdh;
 use crate::AGENT_CONFIG;
 
 const KATA_IMAGE_WORK_DIR: &str = "/run/image/";
 
+// Shared, cid-independent home for cached image bundles. Keeping these out of
+// any single container's `CONTAINER_BASE/{cid}` directory means a bundle
+// survives that container's teardown for as long as other containers still
+// reference it.
+const SHARED_IMAGE_BUNDLE_DIR: &str = "/run/image/bundles";
+
+// Annotation carrying the CDH/KBS resource path of the keywrap-JWE private
+// key used to decrypt an ocicrypt-encrypted image, and the directory the key
+// is staged into once fetched.
+const DECRYPT_KEY_RESOURCE_ANNOTATION: &str = "io.katacontainers.config.agent.decrypt_key_resource";
+const DECRYPT_KEY_DIR: &str = "/run/image/decrypt-keys";
+
+// Annotations carrying per-image registry credentials, as an alternative to
+// a configured auth file.
+const REGISTRY_USERNAME_ANNOTATION: &str = "io.katacontainers.config.agent.registry_username";
+const REGISTRY_PASSWORD_ANNOTATION: &str = "io.katacontainers.config.agent.registry_password";
+
 #[rustfmt::skip]
 lazy_static! {
     pub static ref IMAGE_SERVICE: Mutex<Option<ImageService>> = Mutex::new(None);
@@ -30,10 +51,23 @@ fn sl() -> slog::Logger {
     slog_scope::logger().new(o!("subsystem" => "cgroups"))
 }
 
+// A single unpacked image bundle shared by every container pulling the same
+// manifest digest, kept alive until the last referencing container is torn
+// down.
+struct CachedImage {
+    bundle_path: PathBuf,
+    rootfs: String,
+    containers: std::collections::HashSet<String>,
+}
+
 #[derive(Clone)]
 pub struct ImageService {
     image_client: Arc<Mutex<ImageClient>>,
+    // cid -> cache key, so `remove_image` can find what to release.
     images: Arc<Mutex<HashMap<String, String>>>,
+    // cache key (manifest digest, or the image reference plus cid if none is
+    // known) -> cached bundle.
+    cache: Arc<Mutex<HashMap<String, CachedImage>>>,
 }
 
 impl ImageService {
@@ -43,6 +77,7 @@ impl ImageService {
         Self {
             image_client: Arc::new(Mutex::new(ImageClient::default())),
             images: Arc::new(Mutex::new(HashMap::new())),
+            cache: Arc::new(Mutex::new(HashMap::new())),
         }
     }
 
@@ -55,8 +90,40 @@ impl ImageService {
             .ok_or_else(|| anyhow!("image service is uninitialized"))
     }
 
-    async fn add_image(&self, image: String, cid: String) {
-        self.images.lock().await.insert(image, cid);
+    async fn add_image(&self, cache_key: String, cid: String) {
+        self.images.lock().await.insert(cid, cache_key);
+    }
+
+    /// Release this container's reference to its cached image bundle,
+    /// removing the unpacked layers from disk once no container is using
+    /// them anymore. Must be called by the RPC layer's container-delete
+    /// handler for every `cid` that was ever passed to `pull_image`, or the
+    /// reference is never released and the bundle leaks for the agent's
+    /// lifetime.
+    pub async fn remove_image(&self, cid: &str) -> Result<()> {
+        let cache_key = match self.images.lock().await.remove(cid) {
+            Some(cache_key) => cache_key,
+            None => return Ok(()),
+        };
+
+        let mut cache = self.cache.lock().await;
+        let remove_bundle = match cache.get_mut(&cache_key) {
+            Some(cached) => {
+                cached.containers.remove(cid);
+                cached.containers.is_empty()
+            }
+            None => false,
+        };
+
+        if remove_bundle {
+            if let Some(cached) = cache.remove(&cache_key) {
+                fs::remove_dir_all(&cached.bundle_path).with_context(|| {
+                    format!("failed to remove image bundle {:?}", cached.bundle_path)
+                })?;
+            }
+        }
+
+        Ok(())
     }
 
     /// Set proxy environment from AGENT_CONFIG
@@ -71,11 +138,15 @@ impl ImageService {
         }
     }
 
-    /// pull_image is used for call image-rs to pull image in the guest.
+    /// pull_image is used for call image-rs to pull image in the guest. If
+    /// `image_metadata` carries a manifest digest already present in the
+    /// cache, the existing bundle is reused and no pull happens; the caller
+    /// should call `remove_image` with the same `cid` on container teardown
+    /// to release that reference.
     /// # Parameters
     /// - `image`: Image name (exp: quay.io/prometheus/busybox:latest)
     /// - `cid`: Container id
-    /// - `image_metadata`: Annotations about the image (exp: "containerd.io/snapshot/cri.layer-digest": "sha256:24fb2886d6f6c5d16481dd7608b47e78a8e92a13d6e64d87d57cb16d5f766d63")
+    /// - `image_metadata`: Annotations about the image (exp: "containerd.io/snapshot/cri.image-digest": "sha256:24fb2886d6f6c5d16481dd7608b47e78a8e92a13d6e64d87d57cb16d5f766d63")
     /// # Returns
     /// - The image rootfs bundle path. (exp. /run/kata-containers/cb0b47276ea66ee9f44cc53afa94d7980b57a52c3f306f68cb034e58d9fbd3c6/images/rootfs)
     pub async fn pull_image(
@@ -87,22 +158,75 @@ impl ImageService {
         info!(sl(), "image metadata: {:?}", image_metadata);
         Self::set_proxy_env_vars();
 
-        let bundle_path = Path::new(CONTAINER_BASE).join(cid).join("images");
+        // `cri.layer-digest` names a single layer, not the manifest; using it
+        // as a stand-in for the manifest digest would mis-key the cache
+        // against the wrong artifact and, if policy were enabled, verify a
+        // signature against a digest image-rs never resolves. Only a real
+        // manifest digest annotation is trusted here.
+        let manifest_digest = image_metadata
+            .get("containerd.io/snapshot/cri.image-digest")
+            .cloned();
+        // Without a manifest digest we can't safely share the bundle with
+        // another container, so key the cache on the image reference together
+        // with the cid; it will only ever be reused by a second pull of the
+        // exact same cid.
+        let cache_key = manifest_digest
+            .clone()
+            .unwrap_or_else(|| format!("{}@{}", image, cid));
+
+        if let Some(rootfs) = self
+            .reuse_cached_image(&cache_key, cid)
+            .await
+            .context("failed to check image cache")?
+        {
+            info!(
+                sl(),
+                "image {:?} already pulled, reusing cached bundle for cid {:?}", image, cid
+            );
+            return Ok(rootfs);
+        }
+
+        let bundle_path =
+            Path::new(SHARED_IMAGE_BUNDLE_DIR).join(cache_key.replace(['/', ':'], "_"));
         fs::create_dir_all(&bundle_path)?;
         info!(sl(), "pull image {:?}, bundle path {:?}", cid, bundle_path);
 
+        if !AGENT_CONFIG.image_policy_file.is_empty() {
+            let manifest_digest = manifest_digest.clone().ok_or_else(|| {
+                anyhow!(
+                    "image policy is enabled but no manifest digest was provided for {}",
+                    image
+                )
+            })?;
+            verify_signature(image, &manifest_digest)
+                .await
+                .with_context(|| format!("signature verification failed for image {}", image))?;
+        }
+
+        let decrypt_config = build_decrypt_config(image_metadata)
+            .await
+            .context("failed to build image decryption config")?;
+        let registry_auth = build_registry_auth(image, image_metadata)
+            .context("failed to resolve registry pull credentials")?;
+
         let res = self
             .image_client
             .lock()
             .await
-            .pull_image(image, &bundle_path, &None, &None)
+            .pull_image(
+                image,
+                &bundle_path,
+                &registry_auth.as_deref(),
+                &decrypt_config.as_deref(),
+            )
             .await;
-        match res {
-            Ok(image) => {
+        let resolved_digest = match res {
+            Ok(resolved_digest) => {
                 info!(
                     sl(),
                     "pull and unpack image {:?}, cid: {:?}, with image-rs succeed. ", image, cid
                 );
+                resolved_digest
             }
             Err(e) => {
                 error!(
@@ -115,7 +239,545 @@ impl ImageService {
                 return Err(e);
             }
         };
-        self.add_image(String::from(image), String::from(cid)).await;
-        Ok(format! {"{}/rootfs",bundle_path.display()})
+
+        // image-rs resolves the manifest it actually unpacks independently
+        // of the annotation we verified the signature against; without this
+        // check a host could present a validly-signed digest for one image
+        // while image-rs pulls and unpacks a different one.
+        if let Some(verified_digest) = &manifest_digest {
+            if &resolved_digest != verified_digest {
+                let _ = fs::remove_dir_all(&bundle_path);
+                return Err(anyhow!(
+                    "image {} resolved to manifest digest {} but signature verification was performed against {}; refusing to use this pull",
+                    image,
+                    resolved_digest,
+                    verified_digest
+                ));
+            }
+        }
+
+        let rootfs = format! {"{}/rootfs", bundle_path.display()};
+        self.add_image(cache_key.clone(), String::from(cid)).await;
+        self.cache.lock().await.insert(
+            cache_key,
+            CachedImage {
+                bundle_path,
+                rootfs: rootfs.clone(),
+                containers: std::collections::HashSet::from([String::from(cid)]),
+            },
+        );
+        Ok(rootfs)
+    }
+
+    /// If `cache_key` is already pulled and its bundle is still on disk,
+    /// record `cid` against it and return its rootfs path without pulling
+    /// again.
+    async fn reuse_cached_image(&self, cache_key: &str, cid: &str) -> Result<Option<String>> {
+        let mut cache = self.cache.lock().await;
+        let cached = match cache.get_mut(cache_key) {
+            Some(cached) => cached,
+            None => return Ok(None),
+        };
+
+        if !cached.bundle_path.exists() {
+            cache.remove(cache_key);
+            return Ok(None);
+        }
+
+        cached.containers.insert(cid.to_string());
+        let rootfs = cached.rootfs.clone();
+        drop(cache);
+        self.add_image(cache_key.to_string(), cid.to_string()).await;
+        Ok(Some(rootfs))
+    }
+}
+
+// --- Encrypted images and registry authentication ---------------------------
+
+/// Build an ocicrypt decryption config for an encrypted image, fetching the
+/// keywrap-JWE private key named by `DECRYPT_KEY_RESOURCE_ANNOTATION` from
+/// the CDH/KBS and staging it where image-rs's ocicrypt keyprovider can read
+/// it. Returns `None` for images that aren't encrypted.
+async fn build_decrypt_config(image_metadata: &HashMap<String, String>) -> Result<Option<String>> {
+    let resource_path = match image_metadata.get(DECRYPT_KEY_RESOURCE_ANNOTATION) {
+        Some(resource_path) => resource_path,
+        None => return Ok(None),
+    };
+
+    let key = cdh::get_resource(resource_path)
+        .await
+        .context("failed to fetch image decryption key from the CDH")?;
+
+    fs::create_dir_all(DECRYPT_KEY_DIR)?;
+    let key_path = Path::new(DECRYPT_KEY_DIR).join(resource_path.replace('/', "_"));
+    fs::write(&key_path, key)?;
+
+    Ok(Some(format!(
+        "provider:attestation-agent:keypath={}",
+        key_path.display()
+    )))
+}
+
+/// Resolve `username:password` pull credentials for `image`, preferring the
+/// per-image annotations and falling back to `AGENT_CONFIG.image_registry_auth_file`.
+fn build_registry_auth(
+    image: &str,
+    image_metadata: &HashMap<String, String>,
+) -> Result<Option<String>> {
+    if let (Some(username), Some(password)) = (
+        image_metadata.get(REGISTRY_USERNAME_ANNOTATION),
+        image_metadata.get(REGISTRY_PASSWORD_ANNOTATION),
+    ) {
+        return Ok(Some(format!("{}:{}", username, password)));
+    }
+
+    let auth_file = &AGENT_CONFIG.image_registry_auth_file;
+    if auth_file.is_empty() {
+        return Ok(None);
+    }
+
+    registry_auth_from_file(auth_file, image)
+}
+
+#[derive(Debug, Deserialize)]
+struct RegistryAuthFile {
+    auths: HashMap<String, RegistryAuthEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct RegistryAuthEntry {
+    auth: String,
+}
+
+/// Look up `image`'s registry in a docker-style `auth.json` (`{"auths":
+/// {"registry": {"auth": "base64(user:pass)"}}}`) and decode its credentials.
+fn registry_auth_from_file(auth_file: &str, image: &str) -> Result<Option<String>> {
+    use base64::Engine;
+
+    let data = fs::read_to_string(auth_file)
+        .with_context(|| format!("failed to read registry auth file {}", auth_file))?;
+    let parsed: RegistryAuthFile =
+        serde_json::from_str(&data).context("failed to parse registry auth file")?;
+
+    let registry = image.split('/').next().unwrap_or(image);
+    let entry = match parsed.auths.get(registry) {
+        Some(entry) => entry,
+        None => return Ok(None),
+    };
+
+    let decoded = base64::engine::general_purpose::STANDARD
+        .decode(&entry.auth)
+        .context("failed to decode registry auth entry")?;
+    Ok(Some(String::from_utf8(decoded)?))
+}
+
+// --- Container image signature verification --------------------------------
+//
+// Gated by `AGENT_CONFIG.image_policy_file`, which points at a `policy.json`
+// in the "simple signing" policy format (see containers/image's `Policy`):
+// a `default` requirement list plus `transports.docker` scopes matched
+// against `registry/repo` prefixes of the pulled reference. Verification
+// runs before `add_image`, so a policy mismatch aborts the pull.
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+enum SignedIdentity {
+    #[serde(rename = "matchExact")]
+    ExactMatch,
+    #[serde(rename = "matchRepository")]
+    MatchRepository,
+    #[serde(rename = "remapIdentity")]
+    RemapIdentity { prefix: String, signed_prefix: String },
+}
+
+fn default_signed_identity() -> SignedIdentity {
+    SignedIdentity::ExactMatch
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type")]
+enum PolicyRequirement {
+    #[serde(rename = "insecureAcceptAnything")]
+    InsecureAcceptAnything,
+    #[serde(rename = "reject")]
+    Reject,
+    #[serde(rename = "signedBy")]
+    SignedBy {
+        /// GPG keyring files; a signature is accepted if it verifies against
+        /// any one of them.
+        key_paths: Vec<PathBuf>,
+        /// Base URL of the sigstore/lookaside location serving detached
+        /// signatures for this scope.
+        signature_store: String,
+        #[serde(default = "default_signed_identity")]
+        signed_identity: SignedIdentity,
+    },
+    #[serde(rename = "sigstoreSigned")]
+    SigstoreSigned {
+        fulcio_root: PathBuf,
+        rekor_public_key: PathBuf,
+        #[serde(default = "default_signed_identity")]
+        signed_identity: SignedIdentity,
+    },
+}
+
+#[derive(Debug, Clone, Deserialize)]
+struct ImagePolicy {
+    default: Vec<PolicyRequirement>,
+    #[serde(default)]
+    transports: HashMap<String, HashMap<String, Vec<PolicyRequirement>>>,
+}
+
+impl ImagePolicy {
+    fn load(path: &Path) -> Result<Self> {
+        let data = fs::read_to_string(path)
+            .with_context(|| format!("failed to read image policy file {:?}", path))?;
+        serde_json::from_str(&data).context("failed to parse image policy file")
+    }
+
+    /// The requirements that apply to `image`: the longest matching
+    /// "docker" transport scope, falling back to the policy default.
+    fn requirements_for(&self, image: &str) -> &[PolicyRequirement] {
+        let repository = repository_of(image);
+        self.transports
+            .get("docker")
+            .and_then(|scopes| {
+                scopes
+                    .keys()
+                    .filter(|scope| {
+                        repository == scope.as_str()
+                            || repository.starts_with(&format!("{}/", scope))
+                    })
+                    .max_by_key(|scope| scope.len())
+                    .and_then(|scope| scopes.get(scope))
+            })
+            .map(Vec::as_slice)
+            .unwrap_or(&self.default)
+    }
+}
+
+/// Strip a trailing `:tag` or `@digest` from an image reference, leaving the
+/// bare `registry/repository` that policy scopes are written against. A `:`
+/// before the final `/` is a registry port (e.g. `localhost:5000/busybox`),
+/// not a tag, and must be left alone.
+fn repository_of(image: &str) -> &str {
+    let repo_start = image.rfind('/').map(|slash| slash + 1).unwrap_or(0);
+    match image[repo_start..].find(['@', ':']) {
+        Some(offset) => &image[..repo_start + offset],
+        None => image,
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleSigningPayload {
+    critical: SimpleSigningCritical,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleSigningCritical {
+    image: SimpleSigningImage,
+    identity: SimpleSigningIdentity,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleSigningImage {
+    #[serde(rename = "docker-manifest-digest")]
+    docker_manifest_digest: String,
+}
+
+#[derive(Debug, Deserialize)]
+struct SimpleSigningIdentity {
+    #[serde(rename = "docker-reference")]
+    docker_reference: String,
+}
+
+/// Evaluate `AGENT_CONFIG.image_policy_file` for `image` and return an error
+/// unless every matching requirement is satisfied.
+async fn verify_signature(image: &str, manifest_digest: &str) -> Result<()> {
+    let policy = ImagePolicy::load(Path::new(&AGENT_CONFIG.image_policy_file))?;
+
+    let requirements = policy.requirements_for(image);
+    if requirements.is_empty() {
+        return Err(anyhow!(
+            "image policy has no matching requirement for image {}, refusing to pull",
+            image
+        ));
+    }
+
+    for requirement in requirements {
+        match requirement {
+            PolicyRequirement::InsecureAcceptAnything => {}
+            PolicyRequirement::Reject => {
+                return Err(anyhow!("image policy rejects image {}", image))
+            }
+            PolicyRequirement::SignedBy {
+                key_paths,
+                signature_store,
+                signed_identity,
+            } => {
+                verify_simple_signing(
+                    image,
+                    manifest_digest,
+                    key_paths,
+                    signature_store,
+                    signed_identity,
+                )
+                .await?;
+            }
+            PolicyRequirement::SigstoreSigned { .. } => {
+                // Real cosign/sigstore verification needs the signature
+                // fetched from the registry's OCI referrers API (not a
+                // lookaside URL) and checked with an actual sigstore
+                // client; neither is implemented here yet. Fail closed
+                // rather than accept on a verification path that can't
+                // actually verify anything.
+                return Err(anyhow!(
+                    "sigstoreSigned policy requirements are not supported for image {}",
+                    image
+                ));
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// "Simple signing": fetch the detached, GPG-signed signature for
+/// `manifest_digest` from the lookaside store and accept if it verifies
+/// against any key in `key_paths` and the embedded identity matches.
+async fn verify_simple_signing(
+    image: &str,
+    manifest_digest: &str,
+    key_paths: &[PathBuf],
+    signature_store: &str,
+    signed_identity: &SignedIdentity,
+) -> Result<()> {
+    let signature = fetch_lookaside_signature(signature_store, manifest_digest).await?;
+
+    for key_path in key_paths {
+        let payload = match verify_gpg_signature(&signature, key_path).await {
+            Ok(payload) => payload,
+            Err(_) => continue,
+        };
+
+        let claim: SimpleSigningPayload = serde_json::from_slice(&payload)
+            .context("failed to parse simple signing payload")?;
+        if claim.critical.image.docker_manifest_digest != manifest_digest {
+            continue;
+        }
+        if identity_matches(image, &claim.critical.identity.docker_reference, signed_identity) {
+            return Ok(());
+        }
+    }
+
+    Err(anyhow!(
+        "no signature for image {} verified against the configured key ring",
+        image
+    ))
+}
+
+// Requires `reqwest` (with the `rustls-tls` feature, to match the rest of
+// the agent's TLS stack) as a direct dependency of this crate's Cargo.toml.
+async fn fetch_lookaside_signature(signature_store: &str, manifest_digest: &str) -> Result<Vec<u8>> {
+    let digest = manifest_digest.replace(':', "=");
+    let url = format!("{}/{}/signature-1", signature_store.trim_end_matches('/'), digest);
+    let response = reqwest::get(&url)
+        .await
+        .with_context(|| format!("failed to fetch signature from {}", url))?;
+    if !response.status().is_success() {
+        return Err(anyhow!("no signature found at {}", url));
+    }
+    Ok(response.bytes().await?.to_vec())
+}
+
+/// Verify an OpenPGP signed message against `key_path` and return its
+/// plaintext payload.
+async fn verify_gpg_signature(signature: &[u8], key_path: &Path) -> Result<Vec<u8>> {
+    let gnupg_home = tempfile::tempdir()?;
+    Command::new("gpg")
+        .args(["--homedir"])
+        .arg(gnupg_home.path())
+        .args(["--quiet", "--batch", "--import"])
+        .arg(key_path)
+        .output()
+        .await
+        .context("failed to import signature verification key")?;
+
+    let mut child = Command::new("gpg")
+        .arg("--homedir")
+        .arg(gnupg_home.path())
+        .args(["--quiet", "--batch", "--decrypt"])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .context("failed to invoke gpg")?;
+    child
+        .stdin
+        .take()
+        .ok_or_else(|| anyhow!("failed to open gpg stdin"))?
+        .write_all(signature)
+        .await?;
+    let output = child.wait_with_output().await?;
+    if !output.status.success() {
+        return Err(anyhow!(
+            "gpg signature verification failed: {}",
+            String::from_utf8_lossy(&output.stderr)
+        ));
+    }
+    Ok(output.stdout)
+}
+
+/// Apply a policy's `signedIdentity` rule between the reference being pulled
+/// and the identity embedded in the signature.
+fn identity_matches(image: &str, signed_reference: &str, rule: &SignedIdentity) -> bool {
+    match rule {
+        SignedIdentity::ExactMatch => image == signed_reference,
+        SignedIdentity::MatchRepository => {
+            repository_of(image) == repository_of(signed_reference)
+        }
+        SignedIdentity::RemapIdentity {
+            prefix,
+            signed_prefix,
+        } => {
+            image
+                .strip_prefix(prefix.as_str())
+                .and_then(|suffix| signed_reference.strip_prefix(signed_prefix.as_str()).map(|s| s == suffix))
+                .unwrap_or(false)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn repository_of_strips_tag_and_digest() {
+        assert_eq!(
+            repository_of("docker.io/library/busybox:latest"),
+            "docker.io/library/busybox"
+        );
+        assert_eq!(
+            repository_of("docker.io/library/busybox@sha256:abcd"),
+            "docker.io/library/busybox"
+        );
+        assert_eq!(repository_of("docker.io/library/busybox"), "docker.io/library/busybox");
+    }
+
+    #[test]
+    fn repository_of_keeps_registry_port() {
+        assert_eq!(
+            repository_of("localhost:5000/busybox:latest"),
+            "localhost:5000/busybox"
+        );
+        assert_eq!(repository_of("localhost:5000/busybox"), "localhost:5000/busybox");
+    }
+
+    #[test]
+    fn requirements_for_matches_tagged_reference_against_exact_scope() {
+        let policy = ImagePolicy {
+            default: vec![PolicyRequirement::Reject],
+            transports: HashMap::from([(
+                "docker".to_string(),
+                HashMap::from([(
+                    "docker.io/library/busybox".to_string(),
+                    vec![PolicyRequirement::InsecureAcceptAnything],
+                )]),
+            )]),
+        };
+
+        let requirements = policy.requirements_for("docker.io/library/busybox:latest");
+        assert!(matches!(
+            requirements,
+            [PolicyRequirement::InsecureAcceptAnything]
+        ));
+    }
+
+    #[test]
+    fn requirements_for_falls_back_to_default_when_no_scope_matches() {
+        let policy = ImagePolicy {
+            default: vec![PolicyRequirement::Reject],
+            transports: HashMap::new(),
+        };
+
+        let requirements = policy.requirements_for("docker.io/library/busybox:latest");
+        assert!(matches!(requirements, [PolicyRequirement::Reject]));
+    }
+
+    #[test]
+    fn identity_matches_exact() {
+        let rule = SignedIdentity::ExactMatch;
+        assert!(identity_matches(
+            "docker.io/library/busybox:latest",
+            "docker.io/library/busybox:latest",
+            &rule
+        ));
+        assert!(!identity_matches(
+            "docker.io/library/busybox:latest",
+            "docker.io/library/busybox:1.0",
+            &rule
+        ));
+    }
+
+    #[test]
+    fn identity_matches_repository_ignores_tag() {
+        let rule = SignedIdentity::MatchRepository;
+        assert!(identity_matches(
+            "docker.io/library/busybox:latest",
+            "docker.io/library/busybox:1.0",
+            &rule
+        ));
+        assert!(!identity_matches(
+            "docker.io/library/busybox:latest",
+            "docker.io/library/alpine:latest",
+            &rule
+        ));
+    }
+
+    #[test]
+    fn identity_matches_remap_identity() {
+        let rule = SignedIdentity::RemapIdentity {
+            prefix: "docker.io/library/".to_string(),
+            signed_prefix: "internal-mirror.example.com/".to_string(),
+        };
+        assert!(identity_matches(
+            "docker.io/library/busybox:latest",
+            "internal-mirror.example.com/busybox:latest",
+            &rule
+        ));
+        assert!(!identity_matches(
+            "docker.io/library/busybox:latest",
+            "internal-mirror.example.com/other:latest",
+            &rule
+        ));
+    }
+
+    #[test]
+    fn registry_auth_from_file_decodes_matching_entry() {
+        use base64::Engine;
+        use std::io::Write as _;
+
+        let mut file = tempfile::NamedTempFile::new().unwrap();
+        write!(
+            file,
+            r#"{{"auths": {{"quay.io": {{"auth": "{}"}}}}}}"#,
+            base64::engine::general_purpose::STANDARD.encode("user:pass")
+        )
+        .unwrap();
+
+        let auth = registry_auth_from_file(
+            file.path().to_str().unwrap(),
+            "quay.io/prometheus/busybox:latest",
+        )
+        .unwrap();
+        assert_eq!(auth, Some("user:pass".to_string()));
+
+        let missing = registry_auth_from_file(
+            file.path().to_str().unwrap(),
+            "docker.io/library/busybox:latest",
+        )
+        .unwrap();
+        assert_eq!(missing, None);
     }
 }
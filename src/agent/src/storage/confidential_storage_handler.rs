@@ -5,35 +5,373 @@
 
 use super::new_device;
 use crate::cdh;
+use crate::secret;
 use crate::storage::{StorageContext, StorageHandler};
-use anyhow::{anyhow, Result};
+use anyhow::{anyhow, Context, Result};
+use base64::engine::Engine as _;
 use kata_types::mount::StorageDevice;
+use nix::sys::signal::{self, Signal};
+use nix::unistd::Pid;
 use protocols::agent::Storage;
+use std::collections::HashMap;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
+use tokio::process::{Child, Command};
 use tracing::instrument;
 
 const CONFIDENTIAL_EPHEMERAL_STORAGE: &str = "confidential_ephemeral";
 const CONFIDENTIAL_PERSISTENT_STORAGE: &str = "confidential_persistent";
 
+// Defaults and validation for the ephemeral volume's encryption parameters,
+// overridable via `storage.options()` / annotations.
+const DEFAULT_EPHEMERAL_ENCRYPT_TYPE: &str = "LUKS";
+const SUPPORTED_EPHEMERAL_ENCRYPT_TYPES: &[&str] = &["LUKS", "LUKS2"];
+// dataIntegrity needs the LUKS2 "integrity" sector tag, unavailable in LUKS1.
+const DATA_INTEGRITY_ENCRYPT_TYPE: &str = "LUKS2";
+const DATA_INTEGRITY_SECTOR_TAG: &str = "integrity";
+
+// CDH resource type used to stage the per-bucket key and access credentials for
+// a confidential object storage volume, mirroring "BlockDevice" for LUKS.
+const CONFIDENTIAL_OBJECT_STORAGE: &str = "ObjectStorage";
+
+// goofys mounts a bucket read-through as plain FUSE; it has no concept of
+// per-file decryption, so it's only ever used to expose the ciphertext at a
+// hidden staging directory. Overridable via the "fuseDriver" option.
+const DEFAULT_OBJECT_STORAGE_FUSE_DRIVER: &str = "goofys";
+
+// gocryptfs is layered on top of the staging directory to provide the
+// transparent decryption the request asks for: it understands `-passfile`
+// and mounts a decrypted view of a ciphertext directory at a second mount
+// point. Overridable via the "decryptHelper" option.
+const DEFAULT_OBJECT_STORAGE_DECRYPT_HELPER: &str = "gocryptfs";
+
+// Where the CDH is asked to stage decrypted per-volume key material so it
+// never has to touch the host.
+const OBJECT_STORAGE_KEY_DIR: &str = "/run/kata-containers/cc/object-storage-keys";
+
+// Hidden mountpoint for the ciphertext view of the bucket; gocryptfs mounts
+// the decrypted view on top of this at `storage.mount_point()`.
+const OBJECT_STORAGE_STAGING_DIR: &str = "/run/kata-containers/cc/object-storage-staging";
+
+/// What mounting a confidential persistent volume produced: the path handed
+/// to `new_device`, plus what's needed to tear the volume down again.
+struct ConfidentialObjectStorageMount {
+    storage_path: String,
+    backend_pid: u32,
+    decrypt_pid: u32,
+    staging_dir: PathBuf,
+    key_path: PathBuf,
+}
+
+/// Wraps the plain block/FUSE device so its `cleanup` also stops both FUSE
+/// helpers and wipes the staged key, instead of relying on a side channel the
+/// unmount path would have to know to call separately.
+struct ConfidentialPersistentVolumeDevice {
+    inner: Arc<dyn StorageDevice>,
+    backend_pid: u32,
+    decrypt_pid: u32,
+    staging_dir: PathBuf,
+    key_path: PathBuf,
+}
+
+#[async_trait::async_trait]
+impl StorageDevice for ConfidentialPersistentVolumeDevice {
+    fn path(&self) -> Option<&str> {
+        self.inner.path()
+    }
+
+    async fn cleanup(&self) -> Result<()> {
+        // Tear down the decrypting overlay before the backend it mounts on
+        // top of, so gocryptfs never outlives the ciphertext it depends on.
+        for pid in [self.decrypt_pid, self.backend_pid] {
+            signal::kill(Pid::from_raw(pid as i32), Signal::SIGTERM)
+                .or_else(|e| if e == nix::errno::Errno::ESRCH { Ok(()) } else { Err(e) })
+                .context("failed to stop confidential object storage FUSE helper")?;
+        }
+        wipe_key_file(&self.key_path)?;
+        let _ = fs::remove_dir(&self.staging_dir);
+        self.inner.cleanup().await
+    }
+}
+
 #[derive(Debug)]
 pub struct ConfidentialStorageHandler {}
 
 impl ConfidentialStorageHandler {
     async fn handle_confidential_ephemeral_volume(storage: &Storage) -> Result<String> {
-        let options = std::collections::HashMap::from([
+        let options = parse_storage_options(storage.options());
+
+        let encrypt_type = options
+            .get("encryptType")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_EPHEMERAL_ENCRYPT_TYPE.to_string());
+        if !SUPPORTED_EPHEMERAL_ENCRYPT_TYPES.contains(&encrypt_type.as_str()) {
+            return Err(anyhow!(
+                "unsupported encryptType {:?} for confidential ephemeral volume, expected one of {:?}",
+                encrypt_type,
+                SUPPORTED_EPHEMERAL_ENCRYPT_TYPES
+            ));
+        }
+
+        let data_integrity = parse_bool_option(&options, "dataIntegrity", false)?;
+        if data_integrity && encrypt_type != DATA_INTEGRITY_ENCRYPT_TYPE {
+            return Err(anyhow!(
+                "dataIntegrity requires encryptType={}, got {:?}",
+                DATA_INTEGRITY_ENCRYPT_TYPE,
+                encrypt_type
+            ));
+        }
+
+        let mut secure_mount_options = HashMap::from([
             ("deviceId".to_string(), storage.source().to_string()),
-            ("encryptType".to_string(), "LUKS".to_string()),
-            ("dataIntegrity".to_string(), "False".to_string()),
+            ("encryptType".to_string(), encrypt_type),
+            ("dataIntegrity".to_string(), data_integrity.to_string()),
         ]);
-        cdh::secure_mount("BlockDevice", &options, vec![], storage.mount_point()).await?;
+
+        if data_integrity {
+            // dm-integrity authenticates the block device through a separate
+            // integrity device tagged as the LUKS2 "integrity" sector type.
+            let integrity_device_id = options.get("integrityDeviceId").ok_or_else(|| {
+                anyhow!("dataIntegrity requires an integrityDeviceId option")
+            })?;
+            secure_mount_options.insert(
+                "integrityDeviceId".to_string(),
+                integrity_device_id.clone(),
+            );
+            secure_mount_options.insert(
+                "integrityTag".to_string(),
+                DATA_INTEGRITY_SECTOR_TAG.to_string(),
+            );
+        }
+
+        // The volume key is randomly generated in-guest by default; a
+        // `volumeKeySecret` option derives it from a secret fetched through
+        // the `secret` module instead.
+        if let Some(secret_name) = options.get("volumeKeySecret") {
+            let key = secret::get_secret(secret_name)
+                .await
+                .context("failed to fetch confidential ephemeral volume key from secret store")?;
+            secure_mount_options.insert(
+                "volumeKey".to_string(),
+                base64::engine::general_purpose::STANDARD.encode(key),
+            );
+        }
+
+        cdh::secure_mount(
+            "BlockDevice",
+            &secure_mount_options,
+            vec![],
+            storage.mount_point(),
+        )
+        .await?;
         Ok(storage.mount_point().to_string())
     }
 
-    async fn handle_confidential_persistent_volume(_storage: &Storage) -> Result<String> {
-        Err(anyhow!(
-            "missing the implementation for confidential persistent volume!"
-        ))
+    async fn handle_confidential_persistent_volume(
+        storage: &Storage,
+    ) -> Result<ConfidentialObjectStorageMount> {
+        let options = parse_storage_options(storage.options());
+
+        let bucket_host = options
+            .get("bucketHost")
+            .ok_or_else(|| anyhow!("confidential_persistent storage requires a bucketHost option"))?
+            .clone();
+        let bucket_name = options
+            .get("bucketName")
+            .ok_or_else(|| anyhow!("confidential_persistent storage requires a bucketName option"))?
+            .clone();
+        let object_path = options.get("objectPath").cloned().unwrap_or_default();
+        let fuse_driver = options
+            .get("fuseDriver")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_OBJECT_STORAGE_FUSE_DRIVER.to_string());
+        let decrypt_helper = options
+            .get("decryptHelper")
+            .cloned()
+            .unwrap_or_else(|| DEFAULT_OBJECT_STORAGE_DECRYPT_HELPER.to_string());
+
+        fs::create_dir_all(OBJECT_STORAGE_KEY_DIR)
+            .context("failed to create object storage key directory")?;
+        // `storage.source()` may be an absolute path or contain separators; a
+        // bare `Path::join` would either discard `OBJECT_STORAGE_KEY_DIR` (if
+        // source is absolute) or create subdirectories outside of it, so the
+        // key could be written to or later wiped from an unintended location.
+        let key_path =
+            Path::new(OBJECT_STORAGE_KEY_DIR).join(storage.source().replace('/', "_"));
+
+        // Fetch the per-bucket encryption key and access credentials through the
+        // CDH, the same path `handle_confidential_ephemeral_volume` uses to
+        // unlock its LUKS device, and have it stage them at `key_path` so
+        // plaintext key material never lands on the host.
+        let secure_mount_options = HashMap::from([
+            ("deviceId".to_string(), storage.source().to_string()),
+            ("bucketHost".to_string(), bucket_host.clone()),
+            ("bucketName".to_string(), bucket_name.clone()),
+            ("keyPath".to_string(), key_path.display().to_string()),
+        ]);
+        cdh::secure_mount(
+            CONFIDENTIAL_OBJECT_STORAGE,
+            &secure_mount_options,
+            vec![],
+            storage.mount_point(),
+        )
+        .await
+        .context("failed to stage confidential object storage key through the CDH")?;
+
+        fs::create_dir_all(storage.mount_point())
+            .context("failed to create mount point for confidential persistent volume")?;
+        let staging_dir =
+            Path::new(OBJECT_STORAGE_STAGING_DIR).join(storage.source().replace('/', "_"));
+        fs::create_dir_all(&staging_dir)
+            .context("failed to create staging directory for confidential object storage")?;
+
+        let mount = spawn_object_storage_fuse(
+            &fuse_driver,
+            &decrypt_helper,
+            &bucket_host,
+            &bucket_name,
+            &object_path,
+            &key_path,
+            &staging_dir,
+            storage.mount_point(),
+        )
+        .await
+        .context("failed to launch FUSE helpers for confidential persistent volume")?;
+
+        Ok(ConfidentialObjectStorageMount {
+            storage_path: storage.mount_point().to_string(),
+            backend_pid: mount.backend_pid,
+            decrypt_pid: mount.decrypt_pid,
+            staging_dir,
+            key_path,
+        })
+    }
+}
+
+/// Parse the `key=value` options carried on a `Storage` message into a map.
+fn parse_storage_options(raw_options: &[String]) -> HashMap<String, String> {
+    raw_options
+        .iter()
+        .filter_map(|option| option.split_once('='))
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .collect()
+}
+
+/// Parse a `"True"`/`"False"` style boolean option, case-insensitively,
+/// falling back to `default` when the option isn't set.
+fn parse_bool_option(options: &HashMap<String, String>, key: &str, default: bool) -> Result<bool> {
+    match options.get(key) {
+        None => Ok(default),
+        Some(value) => match value.to_ascii_lowercase().as_str() {
+            "true" => Ok(true),
+            "false" => Ok(false),
+            _ => Err(anyhow!("invalid boolean value {:?} for option {:?}", value, key)),
+        },
+    }
+}
+
+/// What `spawn_object_storage_fuse` started, as pids to tear back down.
+struct ObjectStorageFuseProcesses {
+    backend_pid: u32,
+    decrypt_pid: u32,
+}
+
+/// Neither goofys nor ossfs understand encryption; the transparent decryption
+/// the request asks for comes from layering two real, independently-spawned
+/// FUSE mounts:
+///   1. `fuse_driver` (goofys) exposes the bucket's ciphertext, unmodified,
+///      at the hidden `staging_dir`. goofys takes the optional object prefix
+///      as a `bucket:prefix` mount argument, not a flag.
+///   2. `decrypt_helper` (gocryptfs) mounts a decrypted view of `staging_dir`
+///      at `mount_point`, unlocked with the key the CDH staged at `key_path`
+///      via its real `-passfile` option.
+async fn spawn_object_storage_fuse(
+    fuse_driver: &str,
+    decrypt_helper: &str,
+    bucket_host: &str,
+    bucket_name: &str,
+    object_path: &str,
+    key_path: &Path,
+    staging_dir: &Path,
+    mount_point: &str,
+) -> Result<ObjectStorageFuseProcesses> {
+    let bucket_arg = if object_path.is_empty() {
+        bucket_name.to_string()
+    } else {
+        format!("{}:{}", bucket_name, object_path)
+    };
+
+    let mut backend_command = Command::new(fuse_driver);
+    backend_command
+        .arg("--endpoint")
+        .arg(bucket_host)
+        .arg(&bucket_arg)
+        .arg(staging_dir);
+    let backend_pid = spawn_and_check(fuse_driver, &mut backend_command).await?;
+
+    let mut decrypt_command = Command::new(decrypt_helper);
+    decrypt_command
+        .arg("-passfile")
+        .arg(key_path)
+        .arg(staging_dir)
+        .arg(mount_point);
+    let decrypt_pid = match spawn_and_check(decrypt_helper, &mut decrypt_command).await {
+        Ok(pid) => pid,
+        Err(e) => {
+            let _ = signal::kill(Pid::from_raw(backend_pid as i32), Signal::SIGTERM);
+            return Err(e);
+        }
+    };
+
+    Ok(ObjectStorageFuseProcesses {
+        backend_pid,
+        decrypt_pid,
+    })
+}
+
+/// Spawn a FUSE helper and give it a brief moment to establish its mount
+/// before handing the pid back; a crash at this point surfaces immediately
+/// instead of as a confusing later I/O error on the mountpoint.
+async fn spawn_and_check(name: &str, command: &mut Command) -> Result<u32> {
+    let mut child: Child = command
+        .spawn()
+        .with_context(|| format!("failed to spawn {} FUSE helper", name))?;
+
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    if let Some(status) = child.try_wait()? {
+        return Err(anyhow!(
+            "{} FUSE helper exited early with status {}",
+            name,
+            status
+        ));
     }
+
+    child
+        .id()
+        .ok_or_else(|| anyhow!("{} FUSE helper has no pid after spawning", name))
+}
+
+/// Best-effort secure-delete of a staged key file so decrypted key material
+/// doesn't linger on disk once the volume is unmounted.
+fn wipe_key_file(key_path: &Path) -> Result<()> {
+    if let Ok(metadata) = fs::metadata(key_path) {
+        let zeros = vec![0u8; metadata.len() as usize];
+        if let Ok(mut file) = OpenOptions::new().write(true).open(key_path) {
+            let _ = file.write_all(&zeros);
+            let _ = file.sync_all();
+        }
+    }
+    fs::remove_file(key_path).or_else(|e| {
+        if e.kind() == std::io::ErrorKind::NotFound {
+            Ok(())
+        } else {
+            Err(e)
+        }
+    })?;
+    Ok(())
 }
 
 #[async_trait::async_trait]
@@ -52,15 +390,65 @@ impl StorageHandler for ConfidentialStorageHandler {
         storage: Storage,
         ctx: &mut StorageContext,
     ) -> Result<Arc<dyn StorageDevice>> {
-        let storage_path = match storage.driver() {
+        match storage.driver() {
             CONFIDENTIAL_EPHEMERAL_STORAGE => {
-                Self::handle_confidential_ephemeral_volume(&storage).await?
+                let storage_path = Self::handle_confidential_ephemeral_volume(&storage).await?;
+                new_device(storage_path)
             }
             CONFIDENTIAL_PERSISTENT_STORAGE => {
-                Self::handle_confidential_persistent_volume(&storage).await?
+                let mount = Self::handle_confidential_persistent_volume(&storage).await?;
+                let inner = new_device(mount.storage_path)?;
+                Ok(Arc::new(ConfidentialPersistentVolumeDevice {
+                    inner,
+                    backend_pid: mount.backend_pid,
+                    decrypt_pid: mount.decrypt_pid,
+                    staging_dir: mount.staging_dir,
+                    key_path: mount.key_path,
+                }))
             }
-            _ => return Err(anyhow!("Unsupported storage driver: {}", storage.driver())),
-        };
-        new_device(storage_path)
+            _ => Err(anyhow!("Unsupported storage driver: {}", storage.driver())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_storage_options_splits_on_first_equals() {
+        let options = parse_storage_options(&[
+            "bucketHost=minio.example.com".to_string(),
+            "objectPath=foo=bar".to_string(),
+            "malformed".to_string(),
+        ]);
+        assert_eq!(
+            options.get("bucketHost"),
+            Some(&"minio.example.com".to_string())
+        );
+        assert_eq!(options.get("objectPath"), Some(&"foo=bar".to_string()));
+        assert_eq!(options.len(), 2);
+    }
+
+    #[test]
+    fn parse_bool_option_defaults_when_absent() {
+        let options = HashMap::new();
+        assert!(!parse_bool_option(&options, "dataIntegrity", false).unwrap());
+        assert!(parse_bool_option(&options, "dataIntegrity", true).unwrap());
+    }
+
+    #[test]
+    fn parse_bool_option_is_case_insensitive() {
+        let options = HashMap::from([("dataIntegrity".to_string(), "True".to_string())]);
+        assert!(parse_bool_option(&options, "dataIntegrity", false).unwrap());
+
+        let options = HashMap::from([("dataIntegrity".to_string(), "FALSE".to_string())]);
+        assert!(!parse_bool_option(&options, "dataIntegrity", true).unwrap());
+    }
+
+    #[test]
+    fn parse_bool_option_rejects_invalid_values() {
+        let options = HashMap::from([("dataIntegrity".to_string(), "yes".to_string())]);
+        assert!(parse_bool_option(&options, "dataIntegrity", false).is_err());
     }
 }